@@ -4,174 +4,758 @@ use std::io::{Cursor, Write};
 use ykpack::{DefId, Decoder, Pack, Mir, BasicBlock, Terminator, BasicBlockIndex, CallOperand};
 use fallible_iterator::FallibleIterator;
 use elf;
-use tempfile;
-use std::process::Command;
-use std::collections::HashMap;
+use std::process::{Command, Stdio};
+use std::collections::{HashMap, HashSet, VecDeque};
+use smallvec::SmallVec;
 
-fn write_edge_raw(w: &mut Write, src_node: &str , dest_node: &str, edge_label: Option<&str>) {
-    if let Some(el) = edge_label {
-        writeln!(w, "\"{}\" -> \"{}\" [label = \"{}\"];", src_node.to_string(), dest_node.to_string(), el.to_string()).unwrap();
-    } else {
-        writeln!(w, "\"{}\" -> \"{}\";", src_node.to_string(), dest_node.to_string()).unwrap();
+const BACK_EDGE_ATTRS: &str = "color = red, style = bold";
+const CALL_EDGE_ATTRS: &str = "color = darkgreen, style = dashed";
+
+fn def_id_node_prefix(d: &DefId) -> String {
+    format!("{}-{}", d.crate_hash, d.def_idx)
+}
+
+fn bb_node_id(fn_key: &str, bb: BasicBlockIndex) -> String {
+    format!("{}_{}", fn_key, bb)
+}
+
+fn successors(term: &Terminator) -> SmallVec<[BasicBlockIndex; 4]> {
+    let mut succs = SmallVec::new();
+
+    match term {
+        Terminator::Goto { target_bb } => succs.push(*target_bb),
+        Terminator::FalseEdges { real_target_bb } => succs.push(*real_target_bb),
+        Terminator::FalseUnwind { real_target_bb } => succs.push(*real_target_bb),
+        Terminator::SwitchInt { target_bbs } => succs.extend(target_bbs.iter().cloned()),
+        Terminator::Drop { target_bb, unwind_bb } => {
+            succs.push(*target_bb);
+            if let Some(u_bb) = unwind_bb {
+                succs.push(*u_bb);
+            }
+        },
+        Terminator::DropAndReplace { target_bb, unwind_bb } => {
+            succs.push(*target_bb);
+            if let Some(u_bb) = unwind_bb {
+                succs.push(*u_bb);
+            }
+        },
+        Terminator::Assert { target_bb, cleanup_bb } => {
+            succs.push(*target_bb);
+            if let Some(c_bb) = cleanup_bb {
+                succs.push(*c_bb);
+            }
+        },
+        Terminator::Yield { resume_bb, drop_bb } => {
+            succs.push(*resume_bb);
+            if let Some(d_bb) = drop_bb {
+                succs.push(*d_bb);
+            }
+        },
+        Terminator::Call { cleanup_bb, ret_bb, .. } => {
+            if let Some(r_bb) = ret_bb {
+                succs.push(*r_bb);
+            }
+            if let Some(c_bb) = cleanup_bb {
+                succs.push(*c_bb);
+            }
+        },
+        Terminator::Return
+        | Terminator::Resume
+        | Terminator::Abort
+        | Terminator::Unreachable
+        | Terminator::GeneratorDrop => (),
+    }
+
+    succs
+}
+
+fn reachable_blocks(mir: &Mir) -> HashSet<BasicBlockIndex> {
+    let mut seen = HashSet::new();
+    let mut queue = VecDeque::new();
+
+    seen.insert(0);
+    queue.push_back(0);
+
+    while let Some(bb_idx) = queue.pop_front() {
+        let block = match mir.blocks.get(bb_idx as usize) {
+            Some(block) => block,
+            None => continue,
+        };
+        for succ in successors(&block.term) {
+            if (succ as usize) < mir.blocks.len() && seen.insert(succ) {
+                queue.push_back(succ);
+            }
+        }
     }
+
+    seen
 }
 
-fn write_edge(w: &mut Write, src_node: BasicBlockIndex, dest_node: BasicBlockIndex, edge_label: Option<&str>) {
-    write_edge_raw(w, &src_node.to_string(), &dest_node.to_string(), edge_label);
+fn calculate_predecessors(mir: &Mir) -> HashMap<BasicBlockIndex, Vec<BasicBlockIndex>> {
+    let mut preds: HashMap<BasicBlockIndex, Vec<BasicBlockIndex>> = HashMap::new();
+
+    for (bb_idx, bb_data) in mir.blocks.iter().enumerate() {
+        let bb_idx = bb_idx as u32;
+        for succ in successors(&bb_data.term) {
+            preds.entry(succ).or_default().push(bb_idx);
+        }
+    }
+
+    preds
 }
 
-fn def_id_node_prefix(d: &DefId) -> String {
-    format!("{}-{}", d.crate_hash, d.def_idx)
+#[derive(Clone, Copy, PartialEq)]
+enum DfsColor {
+    White,
+    Gray,
+    Black,
+}
+
+fn find_back_edges_and_rpo(
+    mir: &Mir,
+) -> (HashSet<(BasicBlockIndex, BasicBlockIndex)>, HashMap<BasicBlockIndex, usize>) {
+    let mut color = vec![DfsColor::White; mir.blocks.len()];
+    let mut back_edges = HashSet::new();
+    let mut postorder = Vec::new();
+
+    // Each stack frame is a block paired with its not-yet-visited successors,
+    // so deep/linear CFGs don't blow the native call stack.
+    let mut stack: Vec<(BasicBlockIndex, smallvec::IntoIter<[BasicBlockIndex; 4]>)> = Vec::new();
+    color[0] = DfsColor::Gray;
+    stack.push((0, successors(&mir.blocks[0].term).into_iter()));
+
+    while let Some((bb, succs)) = stack.last_mut() {
+        let bb = *bb;
+        match succs.next() {
+            Some(succ) if succ as usize >= mir.blocks.len() => continue,
+            Some(succ) => match color[succ as usize] {
+                DfsColor::White => {
+                    color[succ as usize] = DfsColor::Gray;
+                    stack.push((succ, successors(&mir.blocks[succ as usize].term).into_iter()));
+                },
+                DfsColor::Gray => {
+                    back_edges.insert((bb, succ));
+                },
+                DfsColor::Black => (),
+            },
+            None => {
+                color[bb as usize] = DfsColor::Black;
+                postorder.push(bb);
+                stack.pop();
+            },
+        }
+    }
+
+    let rpo = postorder
+        .into_iter()
+        .rev()
+        .enumerate()
+        .map(|(rank, bb)| (bb, rank))
+        .collect();
+
+    (back_edges, rpo)
+}
+
+struct CfgStructure {
+    predecessors: HashMap<BasicBlockIndex, Vec<BasicBlockIndex>>,
+    back_edges: HashSet<(BasicBlockIndex, BasicBlockIndex)>,
+    loop_headers: HashSet<BasicBlockIndex>,
+    rpo: HashMap<BasicBlockIndex, usize>,
+}
+
+impl CfgStructure {
+    fn compute(mir: &Mir) -> Self {
+        let predecessors = calculate_predecessors(mir);
+        let (back_edges, rpo) = find_back_edges_and_rpo(mir);
+        let loop_headers = back_edges.iter().map(|&(_src, dest)| dest).collect();
+
+        Self { predecessors, back_edges, loop_headers, rpo }
+    }
+
+    fn in_degree(&self, bb: BasicBlockIndex) -> usize {
+        self.predecessors.get(&bb).map(Vec::len).unwrap_or(0)
+    }
+
+    fn is_back_edge(&self, src: BasicBlockIndex, dest: BasicBlockIndex) -> bool {
+        self.back_edges.contains(&(src, dest))
+    }
+
+    fn is_loop_header(&self, bb: BasicBlockIndex) -> bool {
+        self.loop_headers.contains(&bb)
+    }
+
+    fn rpo_rank(&self, bb: BasicBlockIndex) -> Option<usize> {
+        self.rpo.get(&bb).cloned()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum StatementKind {
+    Assign,
+    StorageLive,
+    StorageDead,
+    SetDiscriminant,
+    FakeRead,
+    Nop,
+    Other,
+}
+
+impl StatementKind {
+    fn classify(stmt: &str) -> Self {
+        let stmt = stmt.trim_start();
+        if stmt.starts_with("StorageLive") {
+            StatementKind::StorageLive
+        } else if stmt.starts_with("StorageDead") {
+            StatementKind::StorageDead
+        } else if stmt.starts_with("SetDiscriminant") {
+            StatementKind::SetDiscriminant
+        } else if stmt.starts_with("FakeRead") {
+            StatementKind::FakeRead
+        } else if stmt.starts_with("Nop") {
+            StatementKind::Nop
+        } else if stmt.contains(" = ") {
+            StatementKind::Assign
+        } else {
+            StatementKind::Other
+        }
+    }
+
+    fn sigil(self) -> &'static str {
+        match self {
+            StatementKind::Assign => "=",
+            StatementKind::StorageLive => "+",
+            StatementKind::StorageDead => "-",
+            StatementKind::SetDiscriminant => "~",
+            StatementKind::FakeRead => "?",
+            StatementKind::Nop => ".",
+            StatementKind::Other => " ",
+        }
+    }
+}
+
+fn statement_span(stmt: &str) -> Option<&str> {
+    stmt.rfind(" // ").map(|idx| stmt[idx + 4..].trim())
+}
+
+struct RenderOptions {
+    prune_unreachable: bool,
+    summary: bool,
+    filter_stmt: Option<String>,
+    group_spans: bool,
+    format: OutputFormat,
+    out_dir: String,
+}
+
+fn detail_statements(block: &BasicBlock, opts: &RenderOptions) -> Vec<String> {
+    if opts.summary {
+        return Vec::new();
+    }
+
+    let mut lines: Vec<String> = Vec::new();
+    let mut group_span: Option<String> = None;
+
+    for stmt in &block.stmts {
+        let text = format!("{:?}", stmt);
+        let kind = StatementKind::classify(&text);
+        let span = statement_span(&text).map(str::to_string);
+
+        let matches = opts.filter_stmt.as_deref().is_none_or(|needle| text.contains(needle));
+        let mut line = format!("{} {}", kind.sigil(), text);
+        if !matches {
+            line = format!("[{}]", line);
+        }
+
+        if opts.group_spans && span.is_some() && span == group_span {
+            let last = lines.last_mut().expect("group_span set implies a prior line");
+            last.push_str("\\n");
+            last.push_str(&line);
+            continue;
+        }
+
+        group_span = span.clone();
+        if opts.group_spans {
+            match &span {
+                Some(s) => lines.push(format!("-- {} --\\n{}", s, line)),
+                None => lines.push(line),
+            }
+        } else {
+            lines.push(line);
+        }
+    }
+
+    lines
+}
+
+struct BlockNodeInfo<'a> {
+    def_id: &'a DefId,
+    bb_idx: BasicBlockIndex,
+    bb_label: &'a str,
+    statements: &'a [String],
+    term_kind: &'a str,
+    attrs: &'a str,
+}
+
+trait CfgSink {
+    fn begin_function(&mut self, def_id: &DefId);
+    fn end_function(&mut self);
+    fn entry_node(&mut self, id: &str);
+    fn block_node(&mut self, id: &str, info: &BlockNodeInfo);
+    fn stub_node(&mut self, id: &str, label: &str, attrs: &str);
+    fn edge(&mut self, src: &str, dest: &str, kind: &str, label: Option<&str>, attrs: Option<&str>);
+    fn finish(&mut self, base_path: &str);
+}
+
+struct DotBuffer {
+    lines: Vec<String>,
+}
+
+impl DotBuffer {
+    fn new(graph_name: &str) -> Self {
+        Self { lines: vec![format!("digraph \"{}\" {{", graph_name), "\tnode [ shape=box ]".to_string()] }
+    }
+
+    fn begin_function(&mut self, def_id: &DefId) {
+        self.lines.push(format!("\tsubgraph \"cluster_{}\" {{", def_id_node_prefix(def_id)));
+        self.lines.push(format!("\t\tlabel = \"{:?}\";", def_id));
+    }
+
+    fn end_function(&mut self) {
+        self.lines.push("\t}".to_string());
+    }
+
+    fn node(&mut self, id: &str, label: &str, attrs: &str) {
+        let mut s = format!("\t\"{}\"[ label = \"{}\"", id, label);
+        if !attrs.is_empty() {
+            s.push_str(&format!(", {}", attrs));
+        }
+        s.push(']');
+        self.lines.push(s);
+    }
+
+    fn edge(&mut self, src: &str, dest: &str, label: Option<&str>, attrs: Option<&str>) {
+        let mut bracket = String::new();
+        if let Some(l) = label {
+            bracket.push_str(&format!("label = \"{}\"", l));
+        }
+        if let Some(a) = attrs {
+            if !bracket.is_empty() {
+                bracket.push_str(", ");
+            }
+            bracket.push_str(a);
+        }
+
+        if bracket.is_empty() {
+            self.lines.push(format!("\t\"{}\" -> \"{}\";", src, dest));
+        } else {
+            self.lines.push(format!("\t\"{}\" -> \"{}\" [{}];", src, dest, bracket));
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut out = self.lines.join("\n");
+        out.push_str("\n}\n");
+        out
+    }
 }
 
-fn style_node(w: &mut dyn Write, node_name: &str, label: Option<&str>, attrs: Option<&str>) {
-    let mut s = format!("\"{}\"", (node_name));
-    let label = label.unwrap_or(node_name);
+struct DotSink {
+    buf: DotBuffer,
+}
 
-    s.push_str(&format!("[ label = \"{}\"", label));
-    if let Some(a) = attrs {
-        s.push_str(&format!(", {}", a));
+impl DotSink {
+    fn new(graph_name: &str) -> Self {
+        Self { buf: DotBuffer::new(graph_name) }
     }
-    s.push(']');
-    writeln!(w, "\t{}", s).unwrap();
 }
 
-fn get_statements_text(blk: &BasicBlock) -> String {
-    let mut lines = Vec::new();
-    for stmt in &blk.stmts {
-        lines.push(format!("{:?}", stmt));
+impl CfgSink for DotSink {
+    fn begin_function(&mut self, def_id: &DefId) {
+        self.buf.begin_function(def_id);
+    }
+
+    fn end_function(&mut self) {
+        self.buf.end_function();
+    }
+
+    fn entry_node(&mut self, id: &str) {
+        self.buf.node(id, "", "shape=point");
+    }
+
+    fn block_node(&mut self, id: &str, info: &BlockNodeInfo) {
+        let label = format!("{{{} | {} | {}}}", info.bb_label, info.statements.join("\\n"), info.term_kind);
+        self.buf.node(id, &label, info.attrs);
+    }
+
+    fn stub_node(&mut self, id: &str, label: &str, attrs: &str) {
+        self.buf.node(id, label, attrs);
+    }
+
+    fn edge(&mut self, src: &str, dest: &str, _kind: &str, label: Option<&str>, attrs: Option<&str>) {
+        self.buf.edge(src, dest, label, attrs);
+    }
+
+    fn finish(&mut self, base_path: &str) {
+        let dot = self.buf.render();
+        if base_path == "-" {
+            print!("{}", dot);
+        } else {
+            std::fs::write(format!("{}.dot", base_path), dot).unwrap();
+        }
+    }
+}
+
+struct GraphvizSink {
+    buf: DotBuffer,
+    graphviz_format: &'static str,
+    extension: &'static str,
+}
+
+impl GraphvizSink {
+    fn new(graph_name: &str, graphviz_format: &'static str, extension: &'static str) -> Self {
+        Self { buf: DotBuffer::new(graph_name), graphviz_format, extension }
+    }
+}
+
+impl CfgSink for GraphvizSink {
+    fn begin_function(&mut self, def_id: &DefId) {
+        self.buf.begin_function(def_id);
+    }
+
+    fn end_function(&mut self) {
+        self.buf.end_function();
+    }
+
+    fn entry_node(&mut self, id: &str) {
+        self.buf.node(id, "", "shape=point");
     }
 
-    lines.join("\\n")
+    fn block_node(&mut self, id: &str, info: &BlockNodeInfo) {
+        let label = format!("{{{} | {} | {}}}", info.bb_label, info.statements.join("\\n"), info.term_kind);
+        self.buf.node(id, &label, info.attrs);
+    }
+
+    fn stub_node(&mut self, id: &str, label: &str, attrs: &str) {
+        self.buf.node(id, label, attrs);
+    }
+
+    fn edge(&mut self, src: &str, dest: &str, _kind: &str, label: Option<&str>, attrs: Option<&str>) {
+        self.buf.edge(src, dest, label, attrs);
+    }
+
+    fn finish(&mut self, base_path: &str) {
+        let out_path = if base_path == "-" {
+            String::from("-")
+        } else {
+            format!("{}.{}", base_path, self.extension)
+        };
+        let mut child = Command::new("dot")
+            .arg(format!("-T{}", self.graphviz_format))
+            .arg(format!("-o{}", out_path))
+            .stdin(Stdio::piped())
+            .spawn()
+            .expect("dot failed");
+        child.stdin.take().unwrap().write_all(self.buf.render().as_bytes()).unwrap();
+        child.wait().expect("dot failed");
+    }
 }
 
-fn write_edges(_mir: &Mir, cx: &mut Context, src_bb: BasicBlockIndex, block: &BasicBlock, fh: &mut dyn Write) {
-    let goto_label = String::from("goto");
-    let ret_label = String::from("ret");
-    let call_label = String::from("call");
-    let cleanup_label = String::from("cleanup");
-    let abort_label = String::from("abort");
-    let false_edge_label = String::from("false edge");
-    let false_unwind_label = String::from("false unwind");
-    let switch_int_label = String::from("switch_int");
-    let resume_label = String::from("resume");
-    let unreach_label = String::from("unreachable");
-    let gen_drop_label = String::from("gen drop");
-    let drop_label = String::from("drop");
-    let unwind_label = String::from("unwind");
-    let drop_replace_label = String::from("drop+replace");
-    let yield_label = String::from("yield");
-    let assert_label = String::from("assert");
+fn json_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
 
+fn json_string_array(items: &[String]) -> String {
+    let quoted: Vec<String> = items.iter().map(|s| format!("\"{}\"", json_escape(s))).collect();
+    format!("[{}]", quoted.join(", "))
+}
+
+struct JsonSink {
+    nodes: Vec<String>,
+    edges: Vec<String>,
+}
+
+impl JsonSink {
+    fn new() -> Self {
+        Self { nodes: Vec::new(), edges: Vec::new() }
+    }
+}
+
+impl CfgSink for JsonSink {
+    fn begin_function(&mut self, _def_id: &DefId) {}
+
+    fn end_function(&mut self) {}
+
+    fn entry_node(&mut self, id: &str) {
+        self.nodes.push(format!("{{\"kind\": \"entry\", \"id\": \"{}\"}}", json_escape(id)));
+    }
+
+    fn block_node(&mut self, id: &str, info: &BlockNodeInfo) {
+        self.nodes.push(format!(
+            "{{\"kind\": \"block\", \"id\": \"{}\", \"def_id\": \"{}\", \"bb_index\": {}, \"statements\": {}, \"terminator_kind\": \"{}\"}}",
+            json_escape(id),
+            json_escape(&format!("{:?}", info.def_id)),
+            info.bb_idx,
+            json_string_array(info.statements),
+            json_escape(info.term_kind),
+        ));
+    }
+
+    fn stub_node(&mut self, id: &str, label: &str, _attrs: &str) {
+        self.nodes.push(format!(
+            "{{\"kind\": \"stub\", \"id\": \"{}\", \"label\": \"{}\"}}",
+            json_escape(id),
+            json_escape(label),
+        ));
+    }
+
+    fn edge(&mut self, src: &str, dest: &str, kind: &str, label: Option<&str>, _attrs: Option<&str>) {
+        let label_json = match label {
+            Some(l) => format!("\"{}\"", json_escape(l)),
+            None => "null".to_string(),
+        };
+        self.edges.push(format!(
+            "{{\"src\": \"{}\", \"dest\": \"{}\", \"kind\": \"{}\", \"label\": {}}}",
+            json_escape(src),
+            json_escape(dest),
+            json_escape(kind),
+            label_json,
+        ));
+    }
+
+    fn finish(&mut self, base_path: &str) {
+        let json = format!("{{\"nodes\": [{}], \"edges\": [{}]}}", self.nodes.join(", "), self.edges.join(", "));
+        if base_path == "-" {
+            println!("{}", json);
+        } else {
+            std::fs::write(format!("{}.json", base_path), json).unwrap();
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum OutputFormat {
+    Dot,
+    Svg,
+    Json,
+    Png,
+}
+
+impl OutputFormat {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "dot" => Some(OutputFormat::Dot),
+            "svg" => Some(OutputFormat::Svg),
+            "json" => Some(OutputFormat::Json),
+            "png" => Some(OutputFormat::Png),
+            _ => None,
+        }
+    }
+
+    fn new_sink(self, graph_name: &str) -> Box<dyn CfgSink> {
+        match self {
+            OutputFormat::Dot => Box::new(DotSink::new(graph_name)),
+            OutputFormat::Svg => Box::new(GraphvizSink::new(graph_name, "svg", "svg")),
+            OutputFormat::Png => Box::new(GraphvizSink::new(graph_name, "png", "png")),
+            OutputFormat::Json => Box::new(JsonSink::new()),
+        }
+    }
+}
+
+struct EdgeSpec<'a> {
+    src_node: &'a str,
+    dest_node: &'a str,
+    src_bb: BasicBlockIndex,
+    dest_bb: BasicBlockIndex,
+    kind: &'a str,
+    label: Option<&'a str>,
+}
+
+fn emit_cfg_edge(sink: &mut dyn CfgSink, cfg: &CfgStructure, spec: &EdgeSpec) {
+    if cfg.is_back_edge(spec.src_bb, spec.dest_bb) {
+        sink.edge(
+            spec.src_node,
+            spec.dest_node,
+            &format!("{} (back edge)", spec.kind),
+            spec.label,
+            Some(BACK_EDGE_ATTRS),
+        );
+    } else {
+        sink.edge(spec.src_node, spec.dest_node, spec.kind, spec.label, None);
+    }
+}
+
+struct RenderCtx<'m, 'b> {
+    mir: &'m Mir,
+    mir_table: &'m HashMap<String, Mir>,
+    cfg: &'m CfgStructure,
+    cx: &'b mut Context,
+    sink: &'b mut dyn CfgSink,
+    pending_calls: &'b mut Vec<&'m Mir>,
+}
+
+fn write_edges(rctx: &mut RenderCtx, src_bb: BasicBlockIndex, block: &BasicBlock, reachable: bool, opts: &RenderOptions) {
+    let fn_key = def_id_node_prefix(&rctx.mir.def_id);
     let src_bb_str = src_bb.to_string();
+    let src_node = bb_node_id(&fn_key, src_bb);
+    let edge = |bb: BasicBlockIndex| bb_node_id(&fn_key, bb);
 
-    let term_label = match block.term {
+    let term_kind = match block.term {
         Terminator::Goto{ target_bb } => {
-            write_edge(fh, src_bb, target_bb, None);
-            &goto_label
+            let spec = EdgeSpec { src_node: &src_node, dest_node: &edge(target_bb), src_bb, dest_bb: target_bb, kind: "goto", label: None };
+            emit_cfg_edge(rctx.sink, rctx.cfg, &spec);
+            "goto"
         },
         Terminator::FalseEdges { real_target_bb } => {
-            write_edge(fh, src_bb, real_target_bb, None);
-            &false_edge_label
+            let spec = EdgeSpec { src_node: &src_node, dest_node: &edge(real_target_bb), src_bb, dest_bb: real_target_bb, kind: "false edge", label: None };
+            emit_cfg_edge(rctx.sink, rctx.cfg, &spec);
+            "false edge"
         },
         Terminator::FalseUnwind { real_target_bb } => {
-            write_edge(fh, src_bb, real_target_bb, None);
-            &false_unwind_label
+            let spec = EdgeSpec { src_node: &src_node, dest_node: &edge(real_target_bb), src_bb, dest_bb: real_target_bb, kind: "false unwind", label: None };
+            emit_cfg_edge(rctx.sink, rctx.cfg, &spec);
+            "false unwind"
         },
         Terminator::SwitchInt{ ref target_bbs } => {
             for target_bb in target_bbs.clone() {
-                write_edge(fh, src_bb, target_bb, None);
+                let spec = EdgeSpec { src_node: &src_node, dest_node: &edge(target_bb), src_bb, dest_bb: target_bb, kind: "switch_int", label: None };
+                emit_cfg_edge(rctx.sink, rctx.cfg, &spec);
             }
-            &switch_int_label
+            "switch_int"
         },
         Terminator::Resume => {
-            let resume_node = cx.external_node_label(resume_label.clone());
-            style_node(fh, &resume_node, None, Some("shape=point, color=blue"));
-            write_edge_raw(fh, &src_bb_str, &resume_node, None);
-            &resume_label
+            let resume_node = rctx.cx.external_node_label(String::from("resume"));
+            rctx.sink.stub_node(&resume_node, "resume", "shape=point, color=blue");
+            rctx.sink.edge(&src_node, &resume_node, "resume", None, None);
+            "resume"
         },
         Terminator::Abort => {
-            let abort_node = cx.external_node_label(abort_label.clone());
-            style_node(fh, &abort_node, None, Some("shape=point, color=red"));
-            write_edge_raw(fh, &src_bb_str, &abort_node, None);
-            &abort_label
+            let abort_node = rctx.cx.external_node_label(String::from("abort"));
+            rctx.sink.stub_node(&abort_node, "abort", "shape=point, color=red");
+            rctx.sink.edge(&src_node, &abort_node, "abort", None, None);
+            "abort"
         },
         Terminator::Return => {
-            let ret_node = cx.external_node_label(ret_label.clone());
-            style_node(fh, &ret_node, None, Some("shape=point"));
-            write_edge_raw(fh, &src_bb_str, &ret_node, None);
-            &ret_label
+            let ret_node = rctx.cx.external_node_label(String::from("ret"));
+            rctx.sink.stub_node(&ret_node, "ret", "shape=point");
+            rctx.sink.edge(&src_node, &ret_node, "return", None, None);
+            "ret"
         },
         Terminator::Unreachable => {
-            let unreach_node = cx.external_node_label(unreach_label.clone());
-            style_node(fh, &unreach_node, None, None);
-            write_edge_raw(fh, &src_bb_str, &unreach_node, None);
-            &unreach_label
+            let unreach_node = rctx.cx.external_node_label(String::from("unreachable"));
+            rctx.sink.stub_node(&unreach_node, "unreachable", "");
+            rctx.sink.edge(&src_node, &unreach_node, "unreachable", None, None);
+            "unreachable"
         },
         Terminator::GeneratorDrop => {
-            let gen_drop_node = cx.external_node_label(gen_drop_label.clone());
-            style_node(fh, &gen_drop_node, None, None);
-            write_edge_raw(fh, &src_bb_str, &gen_drop_node, None);
-            &gen_drop_label
+            let gen_drop_node = rctx.cx.external_node_label(String::from("gen drop"));
+            rctx.sink.stub_node(&gen_drop_node, "gen drop", "");
+            rctx.sink.edge(&src_node, &gen_drop_node, "gen drop", None, None);
+            "gen drop"
         }
         Terminator::Drop { target_bb, unwind_bb } => {
-            write_edge(fh, src_bb, target_bb, None);
+            let spec = EdgeSpec { src_node: &src_node, dest_node: &edge(target_bb), src_bb, dest_bb: target_bb, kind: "drop", label: None };
+            emit_cfg_edge(rctx.sink, rctx.cfg, &spec);
             if let Some(u_bb) = unwind_bb {
-                write_edge(fh, src_bb, u_bb, Some(&unwind_label));
+                let spec = EdgeSpec { src_node: &src_node, dest_node: &edge(u_bb), src_bb, dest_bb: u_bb, kind: "drop", label: Some("unwind") };
+                emit_cfg_edge(rctx.sink, rctx.cfg, &spec);
             }
-            &drop_label
+            "drop"
         },
         Terminator::DropAndReplace { target_bb, unwind_bb } => {
-            write_edge(fh, src_bb, target_bb, None);
+            let spec = EdgeSpec { src_node: &src_node, dest_node: &edge(target_bb), src_bb, dest_bb: target_bb, kind: "drop+replace", label: None };
+            emit_cfg_edge(rctx.sink, rctx.cfg, &spec);
             if let Some(u_bb) = unwind_bb {
-                write_edge(fh, src_bb, u_bb, Some(&unwind_label));
+                let spec = EdgeSpec { src_node: &src_node, dest_node: &edge(u_bb), src_bb, dest_bb: u_bb, kind: "drop+replace", label: Some("unwind") };
+                emit_cfg_edge(rctx.sink, rctx.cfg, &spec);
             }
-            &drop_replace_label
+            "drop+replace"
         },
         Terminator::Assert { target_bb, cleanup_bb } => {
-            write_edge(fh, src_bb, target_bb, None);
+            let spec = EdgeSpec { src_node: &src_node, dest_node: &edge(target_bb), src_bb, dest_bb: target_bb, kind: "assert", label: None };
+            emit_cfg_edge(rctx.sink, rctx.cfg, &spec);
             if let Some(c_bb) = cleanup_bb {
-                write_edge(fh, src_bb, c_bb, Some(&unwind_label));
+                let spec = EdgeSpec { src_node: &src_node, dest_node: &edge(c_bb), src_bb, dest_bb: c_bb, kind: "assert", label: Some("unwind") };
+                emit_cfg_edge(rctx.sink, rctx.cfg, &spec);
             }
-            &assert_label
+            "assert"
         },
         Terminator::Yield { resume_bb: target_bb, drop_bb: except_bb } => {
-            write_edge(fh, src_bb, target_bb, None);
+            let spec = EdgeSpec { src_node: &src_node, dest_node: &edge(target_bb), src_bb, dest_bb: target_bb, kind: "yield", label: None };
+            emit_cfg_edge(rctx.sink, rctx.cfg, &spec);
             if let Some(e_bb) = except_bb {
-                write_edge(fh, src_bb, e_bb, Some(&drop_label));
+                let spec = EdgeSpec { src_node: &src_node, dest_node: &edge(e_bb), src_bb, dest_bb: e_bb, kind: "yield", label: Some("drop") };
+                emit_cfg_edge(rctx.sink, rctx.cfg, &spec);
             }
-            &yield_label
+            "yield"
         },
         Terminator::Call { ref operand, ref cleanup_bb, ref ret_bb } => {
-            let target_node_str = match operand {
-                CallOperand::Fn(def_id) => cx.external_node_label(def_id_node_prefix(def_id)),
-                _ => cx.external_node_label(String::from("???")),
+            let callee_key = match operand {
+                CallOperand::Fn(def_id) => Some(def_id_node_prefix(def_id)),
+                _ => None,
             };
-            style_node(fh, &target_node_str, None, Some("fillcolor = lightblue1, style = filled"));
+            let callee_mir = callee_key.as_ref().and_then(|k| rctx.mir_table.get(k));
+
+            match callee_mir {
+                Some(callee_mir) => {
+                    rctx.pending_calls.push(callee_mir);
+                    let callee_entry = bb_node_id(callee_key.as_ref().unwrap(), 0);
+                    rctx.sink.edge(&src_node, &callee_entry, "call", Some("call"), Some(CALL_EDGE_ATTRS));
+                },
+                None => {
+                    let stub_label = callee_key.unwrap_or_else(|| String::from("???"));
+                    let target_node_str = rctx.cx.external_node_label(stub_label.clone());
+                    rctx.sink.stub_node(&target_node_str, &stub_label, "fillcolor = lightblue1, style = filled");
+                    rctx.sink.edge(&src_node, &target_node_str, "call", None, None);
+                },
+            }
 
-            write_edge_raw(fh, &src_bb_str, &target_node_str, None);
             if let Some(c_bb) = cleanup_bb {
-                write_edge(fh, src_bb, *c_bb, Some(&cleanup_label));
+                let spec = EdgeSpec { src_node: &src_node, dest_node: &edge(*c_bb), src_bb, dest_bb: *c_bb, kind: "call", label: Some("cleanup") };
+                emit_cfg_edge(rctx.sink, rctx.cfg, &spec);
             }
             if let Some(r_bb) = ret_bb {
-                write_edge_raw(fh, &target_node_str, &r_bb.to_string(), None);
+                let spec = EdgeSpec { src_node: &src_node, dest_node: &edge(*r_bb), src_bb, dest_bb: *r_bb, kind: "call", label: Some("ret") };
+                emit_cfg_edge(rctx.sink, rctx.cfg, &spec);
             }
-            &call_label
+            "call"
         },
     };
 
-    let stmts_str = get_statements_text(block);
+    let statements = detail_statements(block, opts);
+
+    let mut bb_label = src_bb_str.clone();
+    if rctx.cfg.is_loop_header(src_bb) {
+        bb_label.push_str(" [loop header]");
+    }
+    bb_label.push_str(&format!(" (in={}", rctx.cfg.in_degree(src_bb)));
+    if let Some(rank) = rctx.cfg.rpo_rank(src_bb) {
+        bb_label.push_str(&format!(", rpo={}", rank));
+    }
+    bb_label.push(')');
 
-    style_node(fh, &src_bb_str, Some(&format!("{{{} | {} | {}}}", src_bb_str, stmts_str, term_label)), Some("shape = record, style=filled, fillcolor=beige"));
+    let attrs = if reachable {
+        "shape = record, style=filled, fillcolor=beige"
+    } else {
+        "shape = record, style=\"filled, dashed\", fillcolor=gray"
+    };
+    let info = BlockNodeInfo {
+        def_id: &rctx.mir.def_id,
+        bb_idx: src_bb,
+        bb_label: &bb_label,
+        statements: &statements,
+        term_kind,
+        attrs,
+    };
+    rctx.sink.block_node(&src_node, &info);
 }
 
 struct Context {
     external_nodes: HashMap<String, usize>,
+    rendered_fns: HashSet<String>,
 }
 
 impl Context {
     fn new() -> Self {
-        Self { external_nodes: HashMap::new() }
+        Self { external_nodes: HashMap::new(), rendered_fns: HashSet::new() }
     }
 
     fn external_node_label(&mut self, node_prefix: String) -> String {
@@ -182,54 +766,157 @@ impl Context {
     }
 }
 
-fn graph(mir: Mir) {
-    let mut fh = tempfile::Builder::new()
-        .prefix(&format!("mir-{}-{}", mir.def_id.crate_hash, mir.def_id.def_idx))
-        .rand_bytes(0)
-        .tempfile_in("mirs")
-        .unwrap();
+fn render_function(
+    mir: &Mir,
+    mir_table: &HashMap<String, Mir>,
+    cx: &mut Context,
+    opts: &RenderOptions,
+    sink: &mut dyn CfgSink,
+) {
+    let fn_key = def_id_node_prefix(&mir.def_id);
+    if !cx.rendered_fns.insert(fn_key.clone()) {
+        return;
+    }
 
-    writeln!(fh, "digraph \"g\" {{").unwrap();
-    writeln!(fh, "\tnode [ shape=box ]").unwrap(); // Default node style.
-    style_node(&mut fh, "__entry", Some(""), Some("shape=point")); // Entry node.
-    write_edge_raw(&mut fh, &"__entry", &"0", None);
+    let reachable = reachable_blocks(mir);
+    let cfg = CfgStructure::compute(mir);
 
-    let mut ctxt = Context::new();
+    sink.begin_function(&mir.def_id);
 
-    for (bb_idx, bb_data) in mir.blocks.iter().enumerate() {
-        write_edges(&mir, &mut ctxt, bb_idx as u32, &bb_data, &mut fh);
+    let mut pending_calls: Vec<&Mir> = Vec::new();
+    let mut rctx = RenderCtx { mir, mir_table, cfg: &cfg, cx: &mut *cx, sink: &mut *sink, pending_calls: &mut pending_calls };
+    for (bb_idx, bb_data) in rctx.mir.blocks.iter().enumerate() {
+        let bb_idx = bb_idx as u32;
+        let is_reachable = reachable.contains(&bb_idx);
+        if opts.prune_unreachable && !is_reachable {
+            continue;
+        }
+        write_edges(&mut rctx, bb_idx, bb_data, is_reachable, opts);
+    }
+
+    rctx.sink.end_function();
+
+    // Render callees as sibling clusters after closing this function's
+    // subgraph, rather than nesting them inside it.
+    for callee_mir in pending_calls {
+        render_function(callee_mir, mir_table, cx, opts, sink);
     }
+}
+
+fn graph(mir: &Mir, mir_table: &HashMap<String, Mir>, opts: &RenderOptions) {
+    let mut sink = opts.format.new_sink("g");
+
+    sink.entry_node("__entry");
+    let entry_key = def_id_node_prefix(&mir.def_id);
+    sink.edge("__entry", &bb_node_id(&entry_key, 0), "entry", None, None);
+
+    let mut ctxt = Context::new();
+    render_function(mir, mir_table, &mut ctxt, opts, &mut *sink);
+
+    let base_path = if opts.out_dir == "-" {
+        String::from("-")
+    } else {
+        format!("{}/mir-{}-{}", opts.out_dir, mir.def_id.crate_hash, mir.def_id.def_idx)
+    };
+    sink.finish(&base_path);
+}
+
+fn render_call_graph(mir_table: &HashMap<String, Mir>, opts: &RenderOptions) {
+    let mut sink = opts.format.new_sink("call_graph");
+    let mut cx = Context::new();
 
-    writeln!(fh, "}}").unwrap();
+    for mir in mir_table.values() {
+        let fn_key = def_id_node_prefix(&mir.def_id);
+        sink.stub_node(&fn_key, &format!("{:?}", mir.def_id), "");
 
-    let output_arg = format!("-o{}.png", fh.path().to_str().unwrap());
-    let mut cmd = Command::new("dot");
-    cmd.arg("-Tpng")
-        .arg(&output_arg)
-        .arg(fh.path());
+        for bb_data in &mir.blocks {
+            if let Terminator::Call { ref operand, .. } = bb_data.term {
+                let callee_key = match operand {
+                    CallOperand::Fn(def_id) => def_id_node_prefix(def_id),
+                    _ => String::from("???"),
+                };
 
-    cmd.status().expect("dot failed");
+                if mir_table.contains_key(&callee_key) {
+                    sink.edge(&fn_key, &callee_key, "call", None, None);
+                } else {
+                    let stub_node = cx.external_node_label(callee_key.clone());
+                    sink.stub_node(&stub_node, &callee_key, "fillcolor = lightblue1, style = filled");
+                    sink.edge(&fn_key, &stub_node, "call", None, None);
+                }
+            }
+        }
+    }
 
-    // Persist the dot file for debugging.
-    let persist_path = format!("{}.dot.txt", fh.path().to_str().unwrap());
-    fh.persist(persist_path).unwrap();
+    let base_path = if opts.out_dir == "-" { String::from("-") } else { format!("{}/call-graph", opts.out_dir) };
+    sink.finish(&base_path);
 }
 
-fn process(path: PathBuf) {
-    let ef = elf::File::open_path(&path).unwrap();
+fn decode_mirs(path: &PathBuf) -> Vec<Mir> {
+    let ef = elf::File::open_path(path).unwrap();
     let sec = ef.get_section(".yk_mir_cfg").unwrap();
     let mut curs = Cursor::new(&sec.data);
     let mut dec = Decoder::from(&mut curs);
 
+    let mut mirs = Vec::new();
     while let Some(pack) = dec.next().unwrap() {
         let Pack::Mir(mir) = pack;
+        mirs.push(mir);
+    }
+    mirs
+}
+
+fn process(path: PathBuf, call_graph: bool, opts: &RenderOptions) {
+    let mir_table: HashMap<String, Mir> = decode_mirs(&path)
+        .into_iter()
+        .map(|mir| (def_id_node_prefix(&mir.def_id), mir))
+        .collect();
+
+    if call_graph {
+        render_call_graph(&mir_table, opts);
+        return;
+    }
+
+    for mir in mir_table.values() {
         println!("{:?}", mir.def_id);
-        graph(mir);
+        graph(mir, &mir_table, opts);
     }
 }
 
 fn main() {
-    let mut args = env::args().skip(1);
-    let bin = args.next().unwrap();
-    process(PathBuf::from(bin));
+    let mut call_graph = false;
+    let mut opts = RenderOptions {
+        prune_unreachable: false,
+        summary: false,
+        filter_stmt: None,
+        group_spans: false,
+        format: OutputFormat::Png,
+        out_dir: String::from("mirs"),
+    };
+    let mut bin = None;
+
+    let mut args = env::args().skip(1).peekable();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--prune-unreachable" => opts.prune_unreachable = true,
+            "--call-graph" => call_graph = true,
+            "--summary" => opts.summary = true,
+            "--group-spans" => opts.group_spans = true,
+            "--filter-stmt" => {
+                let needle = args.next().expect("--filter-stmt requires a substring");
+                opts.filter_stmt = Some(needle);
+            },
+            "--format" => {
+                let value = args.next().expect("--format requires a value (dot, svg, json or png)");
+                opts.format = OutputFormat::parse(&value).expect("--format must be one of: dot, svg, json, png");
+            },
+            "--stdout" => opts.out_dir = String::from("-"),
+            _ => bin = Some(arg),
+        }
+    }
+
+    let bin = bin.expect(
+        "usage: ykpack_explorer [--prune-unreachable] [--call-graph] [--summary] [--group-spans] \
+         [--filter-stmt <substr>] [--format dot|svg|json|png] [--stdout] <binary>",
+    );
+    process(PathBuf::from(bin), call_graph, &opts);
 }